@@ -17,18 +17,31 @@
   const shippingAddressField = document.getElementById("shippingAddress");
 
   function fetchOrders() {
-    fetch("http://localhost:8080/orders")
-      .then(r => r.json())
-      .then(r => orders = r)
-      .then(renderOrders)
-      .catch((e) => {
-        init();
-      });
+    fetch("http://localhost:8080/v1/orders")
+      .then(r => r.json().then(body => ({ ok: r.ok, body })))
+      .then(({ ok, body }) => {
+        if (ok) {
+          orders = body;
+          renderOrders();
+        } else if (body.code === "NO_ORDERS_TABLE") {
+          // Only create the table when it is really missing. Other errors, e.g. a 503 during
+          // maintenance or load shedding, must not wipe the orders.
+          init();
+        } else {
+          displayError(body.detail || body.title);
+        }
+      })
+      .catch((e) => displayError(e));
   }
 
   function init() {
-    fetch("http://localhost:8080/init")
-      .then(() => fetchOrders())
+    fetch("http://localhost:8080/v1/init")
+      .then(r => {
+        if (!r.ok) {
+          throw new Error(`/v1/init failed with HTTP ${r.status}`);
+        }
+        fetchOrders();
+      })
       .catch((e) => displayError(e));
   }
 
@@ -89,7 +102,7 @@
   }
 
   function deleteOrder(orderId) {
-    fetch(`http://localhost:8080/delete_order?id=${orderId}`)
+    fetch(`http://localhost:8080/v1/delete_order?id=${orderId}`)
       .then(() => fetchOrders());
   }
 
@@ -110,7 +123,7 @@
       shipping_address : shippingAddressField.value,
     };
 
-    fetch("http://localhost:8080/create_order", {
+    fetch("http://localhost:8080/v1/create_order", {
       method: "POST",
       body: JSON.stringify(data),
       headers: { "Content-type": "application/json" },
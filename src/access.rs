@@ -0,0 +1,277 @@
+use hyper::header::HeaderMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8`. A bare address is a single
+// host, i.e. a /32 or /128.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack sockets report IPv4 peers as ::ffff:a.b.c.d.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                (u32::from(net) ^ u32::from(ip)) & mask == 0
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                (u128::from(net) ^ u128::from(ip)) & mask == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid address in {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length in {}", s))?,
+            None => max,
+        };
+        // Store an IPv4-mapped network such as ::ffff:10.0.0.0/104 as 10.0.0.0/8, since
+        // contains compares mapped peers as IPv4.
+        if let IpAddr::V6(v6) = addr {
+            if let Some(v4) = v6.to_ipv4_mapped().filter(|_| prefix >= 96) {
+                return Ok(Self { addr: IpAddr::V4(v4), prefix: prefix - 96 });
+            }
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+// CIDR allow and deny lists. Deny wins; an empty allow list allows everyone not denied.
+#[derive(Debug, Clone, Default)]
+pub struct IpAccess {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl IpAccess {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+// The address of the client that sent a request, as worked out by `client_ip`. handle_request
+// stores it in the request extensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+// The address of the client that sent a request. Only when the connection comes from a
+// trusted proxy are the proxy headers consulted: the standard Forwarded header if present,
+// otherwise X-Forwarded-For. Walking the hops right to left, the first address that is not
+// itself a trusted proxy is the client, since anything left of it could have been sent by
+// the client.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[Cidr]) -> IpAddr {
+    let trusted = |ip: IpAddr| trusted_proxies.iter().any(|cidr| cidr.contains(ip));
+    if !trusted(peer) {
+        return peer;
+    }
+    let mut client = peer;
+    for hop in forwarded_hops(headers).into_iter().rev() {
+        match hop {
+            Some(ip) => client = ip,
+            // Garbage or an obfuscated node; the last trusted hop is as far as we can vouch for.
+            None => break,
+        }
+        if !trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+// The addresses a request was forwarded for, in order, from the Forwarded header (RFC 7239)
+// or else X-Forwarded-For. None for hops whose address is missing or not an IP.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::to_string)
+            .collect()
+    };
+    let forwarded = values("Forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect();
+    }
+    values("X-Forwarded-For").iter().map(|node| parse_node(node)).collect()
+}
+
+// The address in a forwarded node such as `192.0.2.60`, `192.0.2.60:4711` or
+// `"[2001:db8::17]:4711"`, without quotes or port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse().ok().or_else(|| {
+        let (ip, _port) = node.rsplit_once(':')?;
+        ip.parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn contains_within_prefix() {
+        let net = cidr("10.1.0.0/16");
+        assert!(net.contains(ip("10.1.0.0")));
+        assert!(net.contains(ip("10.1.255.255")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(!net.contains(ip("2001:db8::1")));
+
+        let net = cidr("2001:db8::/32");
+        assert!(net.contains(ip("2001:db8:ffff::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+        assert!(!net.contains(ip("10.1.0.1")));
+    }
+
+    #[test]
+    fn prefix_zero_contains_its_whole_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn full_prefix_and_bare_address_are_single_hosts() {
+        for net in [cidr("192.0.2.7/32"), cidr("192.0.2.7")] {
+            assert!(net.contains(ip("192.0.2.7")));
+            assert!(!net.contains(ip("192.0.2.6")));
+        }
+        for net in [cidr("2001:db8::7/128"), cidr("2001:db8::7")] {
+            assert!(net.contains(ip("2001:db8::7")));
+            assert!(!net.contains(ip("2001:db8::6")));
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped_peer_matches_ipv4_network() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+    }
+
+    #[test]
+    fn ipv4_mapped_network_is_stored_as_ipv4() {
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert_eq!(cidr("::ffff:192.0.2.7"), cidr("192.0.2.7/32"));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        for s in ["10.0.0.0/33", "2001:db8::/129", "10.0.0.0/", "10.0.0.0/-1", "10.0.0.0/x"] {
+            assert_eq!(s.parse::<Cidr>(), Err(format!("invalid prefix length in {}", s)));
+        }
+        for s in ["", "10.0.0", "example.com/8"] {
+            assert_eq!(s.parse::<Cidr>(), Err(format!("invalid address in {}", s)));
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client_whatever_it_forwards() {
+        let forged = headers(&[("x-forwarded-for", "198.51.100.7"), ("forwarded", "for=198.51.100.7")]);
+        assert_eq!(client_ip(ip("203.0.113.5"), &forged, &[cidr("10.0.0.0/8")]), ip("203.0.113.5"));
+        assert_eq!(client_ip(ip("10.0.0.2"), &forged, &[]), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn walks_trusted_hops_right_to_left() {
+        let trusted = [cidr("10.0.0.0/8")];
+        // The client prepended a forged address; the proxies appended the real one and their own.
+        let chain = headers(&[("x-forwarded-for", "198.51.100.7, 203.0.113.5, 10.0.0.9"), ("x-forwarded-for", "10.0.0.8")]);
+        assert_eq!(client_ip(ip("10.0.0.2"), &chain, &trusted), ip("203.0.113.5"));
+
+        let internal = headers(&[("x-forwarded-for", "10.0.0.5, 10.0.0.9")]);
+        assert_eq!(client_ip(ip("10.0.0.2"), &internal, &trusted), ip("10.0.0.5"));
+        assert_eq!(client_ip(ip("10.0.0.2"), &HeaderMap::new(), &trusted), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn stops_at_an_obfuscated_or_unparsable_hop() {
+        let trusted = [cidr("10.0.0.0/8")];
+        let hidden = headers(&[("forwarded", "for=203.0.113.5, for=_hidden")]);
+        assert_eq!(client_ip(ip("10.0.0.2"), &hidden, &trusted), ip("10.0.0.2"));
+        let hidden = headers(&[("forwarded", "for=_hidden, for=10.0.0.9")]);
+        assert_eq!(client_ip(ip("10.0.0.2"), &hidden, &trusted), ip("10.0.0.9"));
+        let garbage = headers(&[("x-forwarded-for", "unknown")]);
+        assert_eq!(client_ip(ip("10.0.0.2"), &garbage, &trusted), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn forwarded_takes_precedence_over_x_forwarded_for() {
+        let both = headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            ("forwarded", "proto=https;For=\"[2001:db8::17]:4711\";by=10.0.0.2"),
+        ]);
+        assert_eq!(forwarded_hops(&both), [Some(ip("2001:db8::17"))]);
+        assert_eq!(client_ip(ip("10.0.0.2"), &both, &[cidr("10.0.0.0/8")]), ip("2001:db8::17"));
+    }
+
+    #[test]
+    fn parses_forwarded_nodes() {
+        assert_eq!(parse_node(" 192.0.2.60 "), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("192.0.2.60:4711"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("2001:db8::17"), Some(ip("2001:db8::17")));
+        assert_eq!(parse_node("\"[2001:db8::17]:4711\""), Some(ip("2001:db8::17")));
+        assert_eq!(parse_node("\"[2001:db8::17]\""), Some(ip("2001:db8::17")));
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node(""), None);
+    }
+}
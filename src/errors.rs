@@ -0,0 +1,178 @@
+use crate::routes::response_build;
+use crate::storage::{StorageError, TRANSIENT_SERVER_ERRORS};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+// Stable, machine-readable error codes that clients can branch on.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    UnsupportedMediaType,
+    PayloadTooLarge,
+    InvalidBody,
+    InvalidJson,
+    InvalidParameter,
+    InvalidOrder,
+    Conflict,
+    ConstraintViolation,
+    Maintenance,
+    Overloaded,
+    DatabaseBusy,
+    DatabaseTimeout,
+    NoOrdersTable,
+    InternalError,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::InvalidBody | ErrorCode::InvalidJson | ErrorCode::InvalidParameter => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorCode::InvalidOrder | ErrorCode::ConstraintViolation => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::DatabaseTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::Maintenance | ErrorCode::Overloaded | ErrorCode::DatabaseBusy => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrorCode::NoOrdersTable | ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// Maps timeouts, and MySQL server errors caused by the request's data, by contention worth
+// retrying, or by a missing orders table, to an error code and detail. Anything else, e.g. a
+// lost connection, stays an internal error.
+pub fn classify_db_error(e: &StorageError) -> Option<(ErrorCode, String)> {
+    let e = match e {
+        StorageError::Db(mysql_async::Error::Server(e)) => e,
+        StorageError::Timeout(_) => {
+            return Some((
+                ErrorCode::DatabaseTimeout,
+                "The database did not answer in time, please retry later".into(),
+            ))
+        }
+        _ => return None,
+    };
+    match e.code {
+        // ER_DUP_ENTRY
+        1062 => Some((ErrorCode::Conflict, e.message.clone())),
+        // ER_BAD_NULL_ERROR, ER_WARN_DATA_OUT_OF_RANGE, ER_DATA_TOO_LONG, ER_ROW_IS_REFERENCED_2,
+        // ER_NO_REFERENCED_ROW_2, ER_CHECK_CONSTRAINT_VIOLATED
+        1048 | 1264 | 1406 | 1451 | 1452 | 3819 => Some((ErrorCode::ConstraintViolation, e.message.clone())),
+        // ER_LOCK_WAIT_TIMEOUT, ER_LOCK_DEADLOCK, the same errors reads retry
+        code if TRANSIENT_SERVER_ERRORS.contains(&code) => Some((
+            ErrorCode::DatabaseBusy,
+            "The database is busy, please retry later".into(),
+        )),
+        // ER_NO_SUCH_TABLE. Clients such as the demo UI call /init on this, and only on this.
+        1146 => Some((
+            ErrorCode::NoOrdersTable,
+            "The orders table does not exist, call /init to create it".into(),
+        )),
+        _ => None,
+    }
+}
+
+// An RFC 7807 problem details object, sent as application/problem+json.
+#[derive(Serialize, Debug, Clone)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    pub instance: Option<String>,
+    pub code: ErrorCode,
+    pub request_id: Option<u64>,
+}
+
+// Error response with the same CORS headers as regular responses. The problem body is
+// attached as an extension and written out by handle_request, which knows the request
+// path and ID to fill in.
+pub fn error_response(code: ErrorCode, detail: &str) -> Response<Body> {
+    let status = code.status();
+    let mut resp = response_build("");
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+    resp.extensions_mut().insert(Problem {
+        problem_type: "about:blank",
+        title: status.canonical_reason().unwrap_or(""),
+        status: status.as_u16(),
+        detail: detail.to_string(),
+        instance: None,
+        code,
+        request_id: None,
+    });
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn server_error(code: u16) -> StorageError {
+        StorageError::Db(mysql_async::Error::Server(mysql_async::ServerError {
+            code,
+            message: format!("message for {}", code),
+            state: "HY000".into(),
+        }))
+    }
+
+    fn classify(e: &StorageError) -> Option<ErrorCode> {
+        classify_db_error(e).map(|(code, _)| code)
+    }
+
+    #[test]
+    fn data_errors_keep_the_server_message() {
+        assert_eq!(
+            classify_db_error(&server_error(1062)),
+            Some((ErrorCode::Conflict, "message for 1062".into()))
+        );
+        assert_eq!(
+            classify_db_error(&server_error(1406)),
+            Some((ErrorCode::ConstraintViolation, "message for 1406".into()))
+        );
+        assert_eq!(ErrorCode::Conflict.status(), StatusCode::CONFLICT);
+        assert_eq!(ErrorCode::ConstraintViolation.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn lock_errors_are_busy() {
+        assert_eq!(classify(&server_error(1205)), Some(ErrorCode::DatabaseBusy));
+        assert_eq!(classify(&server_error(1213)), Some(ErrorCode::DatabaseBusy));
+        assert_eq!(ErrorCode::DatabaseBusy.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn missing_table_and_timeout() {
+        assert_eq!(classify(&server_error(1146)), Some(ErrorCode::NoOrdersTable));
+        assert_eq!(
+            classify(&StorageError::Timeout(Duration::from_secs(5))),
+            Some(ErrorCode::DatabaseTimeout)
+        );
+        assert_eq!(ErrorCode::DatabaseTimeout.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn other_errors_stay_internal() {
+        assert_eq!(classify(&server_error(1045)), None);
+        let io = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(classify(&StorageError::Db(io.into())), None);
+    }
+}
@@ -0,0 +1,73 @@
+pub mod access;
+pub mod config;
+pub mod errors;
+pub mod models;
+pub mod routes;
+pub mod storage;
+
+use crate::config::{Config, SchemaCheck};
+use crate::models::{Maintenance, MaintenanceMode};
+use crate::routes::AppState;
+use crate::storage::Storage;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Server;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+pub use crate::routes::handle_request;
+
+// Builds the service state from `config`: connects the pools, runs AUTO_MIGRATE and the
+// schema check. Pass the result to `serve`, or call `handle_request` with it directly.
+pub async fn create_app(config: Config) -> Result<Arc<AppState>, Box<dyn std::error::Error + Send + Sync>> {
+    let storage = Storage::new(&config);
+    if config.pool_warm_up {
+        storage.warm_up().await?;
+    }
+
+    // With AUTO_MIGRATE=true, create the orders table if it is missing. Existing data is kept.
+    if config.auto_migrate {
+        storage.create_table_if_missing().await?;
+    }
+
+    // Check the orders table before serving. SCHEMA_CHECK=strict (the default) refuses to
+    // start on a mismatch, read_only starts with writes in maintenance mode, off skips it.
+    let mut maintenance = Maintenance::default();
+    if config.schema_check != SchemaCheck::Off {
+        match storage.orders_schema_diff().await {
+            Ok(None) => println!("The orders table does not exist yet, call /init to create it"),
+            Ok(Some(diff)) if diff.is_empty() => {}
+            Ok(Some(diff)) => {
+                let report = format!("The orders table does not match this build:\n  {}", diff.join("\n  "));
+                if config.schema_check == SchemaCheck::Strict {
+                    return Err(report.into());
+                }
+                eprintln!("{}\nStarting with writes disabled", report);
+                maintenance = Maintenance {
+                    mode: MaintenanceMode::Writes,
+                    message: "The orders table schema does not match this build, writes are disabled".into(),
+                };
+            }
+            Err(e) => eprintln!("Cannot check the orders table schema: {}", e),
+        }
+    }
+
+    Ok(Arc::new(AppState::new(&config, storage, maintenance)))
+}
+
+// Serves `state` on an already bound listener until the server fails.
+pub async fn serve(listener: std::net::TcpListener, state: Arc<AppState>) -> Result<(), hyper::Error> {
+    let make_svc = make_service_fn(|conn: &AddrStream| {
+        let state = state.clone();
+        let remote_addr = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req| {
+                let state = state.clone();
+                // The peer address, for the IP allow and deny lists.
+                req.extensions_mut().insert(remote_addr);
+                handle_request(req, state)
+            }))
+        }
+    });
+    Server::from_tcp(listener)?.serve(make_svc).await
+}
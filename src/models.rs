@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+
+// Matches the VARCHAR(256) column created by /init.
+pub const MAX_SHIPPING_ADDRESS_LEN: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Order {
+    pub order_id: i32,
+    pub product_id: i32,
+    pub quantity: i32,
+    pub amount: f32,
+    pub shipping: f32,
+    pub tax: f32,
+    pub shipping_address: String,
+}
+
+impl Order {
+    pub fn new(
+        order_id: i32,
+        product_id: i32,
+        quantity: i32,
+        amount: f32,
+        shipping: f32,
+        tax: f32,
+        shipping_address: String,
+    ) -> Self {
+        Self {
+            order_id,
+            product_id,
+            quantity,
+            amount,
+            shipping,
+            tax,
+            shipping_address,
+        }
+    }
+
+    // Rejects values MySQL would otherwise silently truncate.
+    pub fn validate(&self) -> Result<(), String> {
+        let len = self.shipping_address.chars().count();
+        if len > MAX_SHIPPING_ADDRESS_LEN {
+            return Err(format!(
+                "shipping_address is {} characters long, the maximum is {}",
+                len, MAX_SHIPPING_ADDRESS_LEN
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Which side of the API is switched off while in maintenance mode.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MaintenanceMode {
+    Off,
+    Writes,
+    Reads,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Maintenance {
+    pub mode: MaintenanceMode,
+    #[serde(default = "default_maintenance_message")]
+    pub message: String,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            mode: MaintenanceMode::Off,
+            message: default_maintenance_message(),
+        }
+    }
+}
+
+fn default_maintenance_message() -> String {
+    "The service is undergoing maintenance, please retry later".into()
+}
@@ -0,0 +1,592 @@
+use crate::access::{client_ip, Cidr, ClientIp, IpAccess};
+use crate::config::Config;
+use crate::errors::{classify_db_error, error_response, ErrorCode, Problem};
+use crate::models::{Maintenance, MaintenanceMode, Order};
+use crate::storage::{Storage, StorageError, WaitAverage};
+use hyper::header::{HeaderValue, ACCEPT, ALLOW, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, LINK, RETRY_AFTER, WWW_AUTHENTICATE};
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, Response};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+// Shared by every request handler.
+pub struct AppState {
+    storage: Storage,
+    maintenance: Mutex<Maintenance>,
+    max_body_bytes: usize,
+    // Requests currently being handled.
+    in_flight: AtomicUsize,
+    load_shed: LoadShed,
+    // Caps on concurrent reads and writes, so neither can take every pooled connection.
+    read_permits: Semaphore,
+    write_permits: Semaphore,
+    // Moving average of the time reads wait for a permit. Queued reads wait here rather than
+    // in the read pool, so load shedding adds it to the pool's wait time.
+    read_permit_wait_ms: WaitAverage,
+    // Sunset header value for the unversioned legacy routes, if a date has been announced.
+    legacy_sunset: Option<HeaderValue>,
+    ip_access: IpAccess,
+    trusted_proxies: Vec<Cidr>,
+    admin_token: Option<String>,
+    next_request_id: AtomicU64,
+    internal_errors: AtomicU64,
+}
+
+// Thresholds above which reads are rejected early with 503. Zero disables a threshold.
+struct LoadShed {
+    max_in_flight: usize,
+    max_db_wait_ms: u64,
+}
+
+impl AppState {
+    pub fn new(config: &Config, storage: Storage, maintenance: Maintenance) -> Self {
+        Self {
+            storage,
+            maintenance: Mutex::new(maintenance),
+            max_body_bytes: config.max_body_bytes,
+            in_flight: AtomicUsize::new(0),
+            load_shed: LoadShed {
+                max_in_flight: config.load_shed_max_in_flight,
+                max_db_wait_ms: config.load_shed_max_db_wait_ms,
+            },
+            read_permits: Semaphore::new(config.read_concurrency),
+            write_permits: Semaphore::new(config.write_concurrency),
+            read_permit_wait_ms: WaitAverage::default(),
+            legacy_sunset: config
+                .legacy_api_sunset
+                .as_deref()
+                .map(|v| HeaderValue::from_str(v).expect("LEGACY_API_SUNSET invalid")),
+            ip_access: IpAccess {
+                allow: config.ip_allow.clone(),
+                deny: config.ip_deny.clone(),
+            },
+            trusted_proxies: config.trusted_proxies.clone(),
+            admin_token: config.admin_token.clone(),
+            next_request_id: AtomicU64::new(1),
+            internal_errors: AtomicU64::new(0),
+        }
+    }
+
+    // True if the service is under enough pressure that reads should be turned away.
+    fn overloaded(&self) -> bool {
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        let db_wait_ms = self.read_permit_wait_ms.get() + self.storage.read_wait_ms();
+        (self.load_shed.max_in_flight > 0 && in_flight > self.load_shed.max_in_flight)
+            || (self.load_shed.max_db_wait_ms > 0 && db_wait_ms > self.load_shed.max_db_wait_ms)
+    }
+}
+
+// Counts a request as in flight until dropped.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+const WRITE_ENDPOINTS: [&str; 5] = ["/init", "/create_order", "/create_orders", "/update_order", "/delete_order"];
+const READ_ENDPOINTS: [&str; 1] = ["/orders"];
+
+// Every route and the methods it supports, besides OPTIONS. Keep in sync with route_request.
+const ROUTES: [(&str, &str); 11] = [
+    ("/", "GET"),
+    ("/echo", "POST"),
+    ("/init", "GET"),
+    ("/create_order", "POST"),
+    ("/create_orders", "POST"),
+    ("/update_order", "POST"),
+    ("/orders", "GET"),
+    ("/delete_order", "GET"),
+    ("/maintenance", "GET, POST"),
+    ("/version", "GET"),
+    ("/slow_queries", "GET"),
+];
+
+fn allowed_methods(path: &str) -> Option<&'static str> {
+    ROUTES.iter().find(|(route, _)| *route == path).map(|(_, methods)| *methods)
+}
+
+// Endpoints that respond with JSON, and the subset of them that take a JSON body.
+const JSON_ENDPOINTS: [&str; 9] = ["/init", "/create_order", "/create_orders", "/update_order", "/orders", "/delete_order", "/maintenance", "/version", "/slow_queries"];
+const JSON_BODY_ENDPOINTS: [&str; 4] = ["/create_order", "/create_orders", "/update_order", "/maintenance"];
+
+// Time spent in the database and in JSON (de)serialization while handling a request,
+// reported to clients in the Server-Timing header. Failed statements count too, since a slow
+// failure such as a timeout is when the header matters most.
+#[derive(Default)]
+struct ServerTiming {
+    db: Duration,
+    serialize: Duration,
+}
+
+impl ServerTiming {
+    fn header(&self, total: Duration) -> String {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        format!(
+            "db;dur={:.1}, serialize;dur={:.1}, total;dur={:.1}",
+            ms(self.db),
+            ms(self.serialize),
+            ms(total)
+        )
+    }
+}
+
+// Handles one request against `state`. This is the whole service; the hyper server in
+// `serve` only wires it to a socket, so it can also be driven in-process.
+pub async fn handle_request(mut req: Request<Body>, state: Arc<AppState>) -> Result<Response<Body>, anyhow::Error> {
+    let start = Instant::now();
+    let request_id = state.next_request_id.fetch_add(1, Ordering::Relaxed);
+    // Requests handled in-process carry no peer address, and so have no client address.
+    let client = req
+        .extensions()
+        .get::<SocketAddr>()
+        .map(|peer| ClientIp(client_ip(peer.ip(), req.headers(), &state.trusted_proxies)));
+    if let Some(client) = client {
+        req.extensions_mut().insert(client);
+    }
+    let path = req.uri().path().to_string();
+    let (route, legacy) = api_route(&path);
+    let mut timing = ServerTiming::default();
+    let mut resp = match route_request(req, route, state.clone(), &mut timing).await {
+        Ok(resp) => resp,
+        // Database errors caused by the request's data, or by lock contention or timeouts that
+        // outlasted any retries, get their own codes so the client knows whether to fix or retry.
+        Err(e) => match e.downcast_ref::<StorageError>().and_then(classify_db_error) {
+            Some((code, detail)) => {
+                eprintln!("request {} hit a database error: {}", request_id, e);
+                let mut resp = error_response(code, &detail);
+                if code == ErrorCode::DatabaseBusy || code == ErrorCode::DatabaseTimeout {
+                    resp.headers_mut().insert(RETRY_AFTER, HeaderValue::from_static("1"));
+                }
+                resp
+            }
+            None => internal_error(&state, request_id, client, &e),
+        },
+    };
+    if let Some(mut problem) = resp.extensions_mut().remove::<Problem>() {
+        problem.instance = Some(path.clone());
+        problem.request_id = Some(request_id);
+        *resp.body_mut() = Body::from(serde_json::to_string(&problem)?);
+    }
+    let server_timing = timing.header(start.elapsed());
+    resp.headers_mut().insert("Server-Timing", HeaderValue::from_str(&server_timing)?);
+    resp.headers_mut().insert("X-Request-Id", HeaderValue::from(request_id));
+    if legacy {
+        resp.headers_mut().insert("Deprecation", HeaderValue::from_static("true"));
+        let successor = format!("</v1{}>; rel=\"successor-version\"", route);
+        resp.headers_mut().insert(LINK, HeaderValue::from_str(&successor)?);
+        if let Some(sunset) = &state.legacy_sunset {
+            resp.headers_mut().insert("Sunset", sunset.clone());
+        }
+    }
+    Ok(resp)
+}
+
+// Answers with a JSON 500 rather than dropping the connection. The request ID ties the
+// response to the log line carrying the actual error.
+fn internal_error(state: &AppState, request_id: u64, client: Option<ClientIp>, e: &anyhow::Error) -> Response<Body> {
+    let errors = state.internal_errors.fetch_add(1, Ordering::Relaxed) + 1;
+    let client = client.map(|ClientIp(ip)| ip.to_string()).unwrap_or_else(|| "-".into());
+    eprintln!(
+        "request {} from {} failed ({} internal errors so far): {:#}",
+        request_id, client, errors, e
+    );
+    error_response(ErrorCode::InternalError, "Internal server error")
+}
+
+// Maps a request path to the route it addresses within its API version, and whether it
+// used a deprecated unversioned alias. `/v1/orders` and the legacy `/orders` both map to
+// `/orders`. Paths under other prefixes, e.g. a future `/v2`, are left as is, so they only
+// match routes added for that version.
+fn api_route(path: &str) -> (&str, bool) {
+    match path.strip_prefix("/v1") {
+        Some(route) if route.starts_with('/') => (route, false),
+        _ => (path, JSON_ENDPOINTS.contains(&path)),
+    }
+}
+
+async fn route_request(
+    req: Request<Body>,
+    path: &str,
+    state: Arc<AppState>,
+    timing: &mut ServerTiming,
+) -> Result<Response<Body>, anyhow::Error> {
+    let _in_flight = InFlight::new(&state.in_flight);
+
+    // Requests handled in-process have no client address and are not filtered.
+    if let Some(&ClientIp(ip)) = req.extensions().get::<ClientIp>() {
+        if !state.ip_access.allows(ip) {
+            return Ok(error_response(ErrorCode::Forbidden, &format!("Requests from {} are not allowed", ip)));
+        }
+    }
+
+    // Unknown paths get 404 and known paths with the wrong method 405. OPTIONS (including
+    // CORS preflight) is answered here for every route.
+    let allow = match allowed_methods(path) {
+        Some(allow) => allow,
+        None => return Ok(error_response(ErrorCode::NotFound, "No such endpoint, see / for the valid endpoints")),
+    };
+    if req.method() == Method::OPTIONS {
+        let mut resp = response_build("");
+        resp.headers_mut().insert(ALLOW, HeaderValue::from_str(&format!("{}, OPTIONS", allow))?);
+        return Ok(resp);
+    }
+    if !allow.split(", ").any(|method| method == req.method().as_str()) {
+        let mut resp = error_response(
+            ErrorCode::MethodNotAllowed,
+            &format!("{} is not supported here, use {}", req.method(), allow),
+        );
+        resp.headers_mut().insert(ALLOW, HeaderValue::from_str(&format!("{}, OPTIONS", allow))?);
+        return Ok(resp);
+    }
+
+    if JSON_ENDPOINTS.contains(&path) && !accepts_json(&req) {
+        return Ok(error_response(
+            ErrorCode::NotAcceptable,
+            "This endpoint only produces application/json",
+        ));
+    }
+    if req.method() == Method::POST && JSON_BODY_ENDPOINTS.contains(&path) && !is_json_content_type(&req) {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("none");
+        return Ok(error_response(
+            ErrorCode::UnsupportedMediaType,
+            &format!("Unsupported Content-Type '{}', expected application/json", content_type),
+        ));
+    }
+
+    let maintenance = state.maintenance.lock().unwrap().clone();
+    let blocked = match maintenance.mode {
+        MaintenanceMode::Off => false,
+        MaintenanceMode::Writes => WRITE_ENDPOINTS.contains(&path),
+        MaintenanceMode::Reads => READ_ENDPOINTS.contains(&path),
+    };
+    if blocked {
+        return Ok(error_response(ErrorCode::Maintenance, &maintenance.message));
+    }
+
+    // Shed reads first so that writes keep their share of the pool under pressure.
+    if READ_ENDPOINTS.contains(&path) && state.overloaded() {
+        // A shed read waits for nothing. Counting it as such lets the wait averages fall back
+        // below the threshold; otherwise only reads that get through would update them.
+        state.read_permit_wait_ms.record(0);
+        state.storage.record_shed_read();
+        let mut resp = error_response(
+            ErrorCode::Overloaded,
+            "The service is overloaded, please retry later",
+        );
+        resp.headers_mut().insert(RETRY_AFTER, HeaderValue::from_static("1"));
+        return Ok(resp);
+    }
+
+    // Wait for a slot in the route's concurrency budget; held until the response is built.
+    let _permit = if READ_ENDPOINTS.contains(&path) {
+        let _wait = state.read_permit_wait_ms.start();
+        Some(state.read_permits.acquire().await?)
+    } else if WRITE_ENDPOINTS.contains(&path) {
+        Some(state.write_permits.acquire().await?)
+    } else {
+        None
+    };
+
+    match (req.method(), path) {
+        (&Method::GET, "/") => Ok(Response::new(Body::from(
+            "The valid endpoints are /v1/init /v1/create_order /v1/create_orders /v1/update_order /v1/orders /v1/delete_order /v1/maintenance /v1/version /v1/slow_queries",
+        ))),
+
+        // Simply echo the body back to the client.
+        (&Method::POST, "/echo") => Ok(Response::new(req.into_body())),
+
+        (&Method::GET, "/init") => {
+            let db_start = Instant::now();
+            let result = state.storage.init().await;
+            timing.db += db_start.elapsed();
+            result?;
+            Ok(response_build("{\"status\":true}"))
+        }
+
+        (&Method::POST, "/create_order") => {
+            let order: Order = match read_json(req, state.max_body_bytes, timing).await {
+                Ok(order) => order,
+                Err(resp) => return Ok(resp),
+            };
+            if let Err(e) = order.validate() {
+                return Ok(error_response(ErrorCode::InvalidOrder, &e));
+            }
+
+            let db_start = Instant::now();
+            let result = state.storage.create_order(&order).await;
+            timing.db += db_start.elapsed();
+            result?;
+            Ok(response_build("{\"status\":true}"))
+            // Ok(Response::new(Body::from("{\"status\":true}")))
+        }
+
+        (&Method::POST, "/create_orders") => {
+            let orders: Vec<Order> = match read_json(req, state.max_body_bytes, timing).await {
+                Ok(orders) => orders,
+                Err(resp) => return Ok(resp),
+            };
+            for (i, order) in orders.iter().enumerate() {
+                if let Err(e) = order.validate() {
+                    return Ok(error_response(ErrorCode::InvalidOrder, &format!("orders[{}]: {}", i, e)));
+                }
+            }
+
+            let db_start = Instant::now();
+            let result = state.storage.create_orders(&orders).await;
+            timing.db += db_start.elapsed();
+            result?;
+            Ok(response_build("{\"status\":true}"))
+            // Ok(Response::new(Body::from("{\"status\":true}")))
+        }
+
+        (&Method::POST, "/update_order") => {
+            let order: Order = match read_json(req, state.max_body_bytes, timing).await {
+                Ok(order) => order,
+                Err(resp) => return Ok(resp),
+            };
+            if let Err(e) = order.validate() {
+                return Ok(error_response(ErrorCode::InvalidOrder, &e));
+            }
+
+            let db_start = Instant::now();
+            let result = state.storage.update_order(&order).await;
+            timing.db += db_start.elapsed();
+            result?;
+            Ok(response_build("{\"status\":true}"))
+            // Ok(Response::new(Body::from("{\"status\":true}")))
+        }
+
+        (&Method::GET, "/orders") => {
+            let db_start = Instant::now();
+            let orders = state.storage.orders().await;
+            timing.db += db_start.elapsed();
+            let orders = orders?;
+            let serialize_start = Instant::now();
+            let body = serde_json::to_string(&orders)?;
+            timing.serialize += serialize_start.elapsed();
+            Ok(response_build(body.as_str()))
+            // Ok(Response::new(Body::from(serde_json::to_string(&orders)?)))
+        }
+
+        (&Method::GET, "/delete_order") => {
+            let params: HashMap<String, String> = req.uri().query().map(|v| {
+                url::form_urlencoded::parse(v.as_bytes()).into_owned().collect()
+            }).unwrap_or_else(HashMap::new);
+            // A missing id deletes nothing. A non-numeric one is rejected rather than passed on,
+            // since MySQL would cast e.g. '2x' to 2 and delete that order.
+            let order_id = match params.get("id").map(|id| id.parse::<i32>()) {
+                Some(Ok(order_id)) => Some(order_id),
+                Some(Err(_)) => {
+                    return Ok(error_response(ErrorCode::InvalidParameter, "id must be an integer"))
+                }
+                None => None,
+            };
+
+            let db_start = Instant::now();
+            let result = state.storage.delete_order(order_id).await;
+            timing.db += db_start.elapsed();
+            result?;
+            Ok(response_build("{\"status\":true}"))
+            // Ok(Response::new(Body::from("{\"status\":true}")))
+        }
+
+        (&Method::GET, "/maintenance") => {
+            let maintenance = state.maintenance.lock().unwrap().clone();
+            Ok(response_build(serde_json::to_string(&maintenance)?.as_str()))
+        }
+
+        (&Method::POST, "/maintenance") => {
+            if !is_admin(&req, state.admin_token.as_deref()) {
+                return Ok(unauthorized("Changing the maintenance mode requires the admin token"));
+            }
+            let maintenance: Maintenance = match read_json(req, state.max_body_bytes, timing).await {
+                Ok(maintenance) => maintenance,
+                Err(resp) => return Ok(resp),
+            };
+
+            *state.maintenance.lock().unwrap() = maintenance.clone();
+            Ok(response_build(serde_json::to_string(&maintenance)?.as_str()))
+        }
+
+        (&Method::GET, "/version") => {
+            let features: Vec<&str> = env!("BUILD_FEATURES").split(',').filter(|f| !f.is_empty()).collect();
+            let version = serde_json::json!({
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+                "git_sha": env!("BUILD_GIT_SHA"),
+                "build_timestamp": env!("BUILD_TIMESTAMP"),
+                "features": features,
+            });
+            Ok(response_build(&version.to_string()))
+        }
+
+        (&Method::GET, "/slow_queries") => {
+            if !is_admin(&req, state.admin_token.as_deref()) {
+                return Ok(unauthorized("Reading the slow queries requires the admin token"));
+            }
+            let slow_queries = state.storage.slow_queries();
+            Ok(response_build(serde_json::to_string(&slow_queries)?.as_str()))
+        }
+
+        // Return the 404 Not Found for other routes.
+        _ => {
+            Ok(error_response(ErrorCode::NotFound, "No such endpoint, see / for the valid endpoints"))
+        }
+    }
+}
+
+// Reads and deserializes a JSON request body of at most `max_bytes` bytes. On failure,
+// returns the error response to send back: 413 for oversized bodies, 400 for bad JSON.
+async fn read_json<T: DeserializeOwned>(
+    req: Request<Body>,
+    max_bytes: usize,
+    timing: &mut ServerTiming,
+) -> Result<T, Response<Body>> {
+    let too_large = || {
+        error_response(
+            ErrorCode::PayloadTooLarge,
+            &format!("Request body exceeds the limit of {} bytes", max_bytes),
+        )
+    };
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > max_bytes) {
+        return Err(too_large());
+    }
+
+    // Read chunk by chunk, so a chunked upload without Content-Length is cut off at the limit
+    // rather than buffered in full.
+    let mut body = req.into_body();
+    let mut byte_stream = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| error_response(ErrorCode::InvalidBody, &e.to_string()))?;
+        if byte_stream.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        byte_stream.extend_from_slice(&chunk);
+    }
+    let start = Instant::now();
+    let value = serde_json::from_slice(&byte_stream)
+        .map_err(|e| error_response(ErrorCode::InvalidJson, &e.to_string()));
+    timing.serialize += start.elapsed();
+    value
+}
+
+// True if no admin token is configured, or the request carries it as a bearer token.
+fn is_admin(req: &Request<Body>, admin_token: Option<&str>) -> bool {
+    let admin_token = match admin_token {
+        Some(admin_token) => admin_token,
+        None => return true,
+    };
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+    // Compare in constant time, so response timing doesn't leak how much of a guess matched.
+    token.len() == admin_token.len()
+        && token.bytes().zip(admin_token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// 401 asking for the admin token as a bearer token.
+fn unauthorized(detail: &str) -> Response<Body> {
+    let mut resp = error_response(ErrorCode::Unauthorized, detail);
+    resp.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    resp
+}
+
+// True if the Content-Type header is application/json (parameters such as charset are allowed).
+fn is_json_content_type(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().eq_ignore_ascii_case("application/json"))
+        .unwrap_or(false)
+}
+
+// True if the Accept header is missing or allows a JSON response.
+fn accepts_json(req: &Request<Body>) -> bool {
+    let accept = match req.headers().get(ACCEPT).and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept,
+        None => return true,
+    };
+    accept.split(',').any(|range| {
+        let media_type = range.split(';').next().unwrap_or("").trim();
+        media_type.eq_ignore_ascii_case("application/json")
+            || media_type.eq_ignore_ascii_case("application/*")
+            || media_type == "*/*"
+    })
+}
+
+// CORS headers
+pub(crate) fn response_build(body: &str) -> Response<Body> {
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "GET, POST, OPTIONS")
+        .header("Access-Control-Allow-Headers", "api,Keep-Alive,User-Agent,Content-Type,Authorization")
+        .header("Timing-Allow-Origin", "*")
+        .body(Body::from(body.to_owned()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MEMORY_DATABASE_URL;
+    use hyper::StatusCode;
+
+    fn app(config: Config) -> Arc<AppState> {
+        let storage = Storage::new(&config);
+        Arc::new(AppState::new(&config, storage, Maintenance::default()))
+    }
+
+    async fn get_orders(state: &Arc<AppState>) -> StatusCode {
+        let req = Request::get("/v1/orders").body(Body::empty()).unwrap();
+        handle_request(req, state.clone()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn load_shedding_recovers_once_reads_stop_waiting() {
+        let state = app(Config {
+            database_url: MEMORY_DATABASE_URL.into(),
+            load_shed_max_db_wait_ms: 500,
+            ..Config::default()
+        });
+        // One very slow permit wait pushes the average to 10 s.
+        state.read_permit_wait_ms.record(80_000);
+        assert!(state.overloaded());
+
+        let mut shed = 0;
+        while get_orders(&state).await == StatusCode::SERVICE_UNAVAILABLE {
+            shed += 1;
+            assert!(shed < 100, "reads are still shed after {} requests", shed);
+        }
+        // 10 s decays by 1/8 per shed read and drops below 500 ms after 23 of them.
+        assert_eq!(shed, 23);
+        assert!(!state.overloaded());
+        assert_eq!(get_orders(&state).await, StatusCode::OK);
+    }
+}
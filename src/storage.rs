@@ -0,0 +1,586 @@
+use crate::config::{Config, MEMORY_DATABASE_URL};
+use crate::models::Order;
+use mysql_async::prelude::*;
+use mysql_async::{params, Conn, Opts, OptsBuilder, Pool, PoolConstraints, PoolOpts};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Used by /init and by AUTO_MIGRATE on startup. Keep in sync with ORDERS_COLUMNS.
+const CREATE_ORDERS_TABLE: &str = "CREATE TABLE IF NOT EXISTS orders (order_id INT, product_id INT, quantity INT, amount FLOAT, shipping FLOAT, tax FLOAT, shipping_address VARCHAR(256));";
+
+const DROP_ORDERS_TABLE: &str = "DROP TABLE IF EXISTS orders;";
+const INSERT_ORDER: &str = "INSERT INTO orders (order_id, product_id, quantity, amount, shipping, tax, shipping_address) VALUES (:order_id, :product_id, :quantity, :amount, :shipping, :tax, :shipping_address)";
+const UPDATE_ORDER: &str = "UPDATE orders SET product_id=:product_id, quantity=:quantity, amount=:amount, shipping=:shipping, tax=:tax, shipping_address=:shipping_address WHERE order_id=:order_id";
+const SELECT_ORDERS: &str = "SELECT * FROM orders";
+const DELETE_ORDER: &str = "DELETE FROM orders WHERE order_id=:order_id";
+
+// The columns /init creates, in order, as (name, DATA_TYPE, CHARACTER_MAXIMUM_LENGTH).
+// /orders maps `SELECT *` rows positionally, so the order matters too.
+const ORDERS_COLUMNS: [(&str, &str, Option<u64>); 7] = [
+    ("order_id", "int", None),
+    ("product_id", "int", None),
+    ("quantity", "int", None),
+    ("amount", "float", None),
+    ("shipping", "float", None),
+    ("tax", "float", None),
+    ("shipping_address", "varchar", Some(256)),
+];
+
+// How many recent slow queries /slow_queries returns.
+const SLOW_QUERY_LOG_SIZE: usize = 100;
+
+// Moving average of how long something waited, in ms. Each sample moves it 1/8 of the way.
+#[derive(Debug, Default)]
+pub(crate) struct WaitAverage(AtomicU64);
+
+impl WaitAverage {
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, waited_ms: u64) {
+        let average = self.0.load(Ordering::Relaxed);
+        self.0.store((average * 7 + waited_ms) / 8, Ordering::Relaxed);
+    }
+
+    // Times a wait until the returned timer is dropped, so a wait abandoned by a timeout or a
+    // client disconnect is counted too.
+    pub(crate) fn start(&self) -> WaitTimer<'_> {
+        WaitTimer {
+            average: self,
+            start: Instant::now(),
+        }
+    }
+}
+
+pub(crate) struct WaitTimer<'a> {
+    average: &'a WaitAverage,
+    start: Instant,
+}
+
+impl Drop for WaitTimer<'_> {
+    fn drop(&mut self) {
+        self.average.record(self.start.elapsed().as_millis() as u64);
+    }
+}
+
+// A connection pool and the moving average of the time spent waiting for one of its connections.
+struct DbPool {
+    pool: Pool,
+    wait_ms: WaitAverage,
+    min: usize,
+    max: usize,
+    // Ping connections before handing them out, replacing ones the server has closed.
+    pre_ping: bool,
+}
+
+impl DbPool {
+    fn new(url: &str, (min, max): (usize, usize), pre_ping: bool) -> Self {
+        let opts = Opts::from_url(url).unwrap();
+        let builder = OptsBuilder::from_opts(opts);
+        let constraints = PoolConstraints::new(min, max).expect("invalid pool size");
+        let pool_opts = PoolOpts::default().with_constraints(constraints);
+        Self {
+            pool: Pool::new(builder.pool_opts(pool_opts)),
+            wait_ms: WaitAverage::default(),
+            min,
+            max,
+            pre_ping,
+        }
+    }
+
+    // Opens the pool's minimum number of connections up front instead of on first use.
+    async fn warm_up(&self) -> Result<(), mysql_async::Error> {
+        let mut conns = Vec::with_capacity(self.min);
+        for _ in 0..self.min {
+            conns.push(self.pool.get_conn().await?);
+        }
+        // Dropping the connections returns them to the pool.
+        Ok(())
+    }
+
+    // Gets a pooled connection, recording how long the pool made us wait.
+    async fn get_conn(&self) -> Result<Conn, mysql_async::Error> {
+        let _wait = self.wait_ms.start();
+        if !self.pre_ping {
+            return self.pool.get_conn().await;
+        }
+        // After an idle period every pooled connection may have been closed by the server, e.g.
+        // after wait_timeout, so keep replacing them. Once `max` stale ones are gone, the pool
+        // has to open a fresh connection.
+        let mut stale = 0;
+        loop {
+            let mut conn = self.pool.get_conn().await?;
+            match conn.ping().await {
+                Ok(()) => return Ok(conn),
+                Err(e) => {
+                    let _ = conn.disconnect().await;
+                    stale += 1;
+                    if stale > self.max {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SlowQuery {
+    // The statement with its :named placeholders, so parameter values are never recorded.
+    pub sql: String,
+    pub duration_ms: u64,
+    pub rows: u64,
+    // Why the statement failed, e.g. "ERROR 1205 (HY000)". Only the code and SQLSTATE, since
+    // server messages can quote values, e.g. ER_DUP_ENTRY's "Duplicate entry '...'".
+    pub error: Option<String>,
+    // Seconds since the Unix epoch.
+    pub finished_at: u64,
+}
+
+// Why a storage operation failed.
+#[derive(Debug)]
+pub enum StorageError {
+    Db(mysql_async::Error),
+    // The operation did not finish within the route's timeout and was abandoned.
+    Timeout(Duration),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Db(e) => write!(f, "{}", e),
+            StorageError::Timeout(timeout) => write!(f, "database operation timed out after {:?}", timeout),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Db(e) => Some(e),
+            StorageError::Timeout(_) => None,
+        }
+    }
+}
+
+// Runs a database operation, giving up after `timeout` if there is one. Dropping the
+// operation drops its connection, so a hung statement doesn't hold on to it.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    operation: impl Future<Output = Result<T, mysql_async::Error>>,
+) -> Result<T, StorageError> {
+    match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, operation).await {
+            Ok(result) => result.map_err(StorageError::Db),
+            Err(_) => Err(StorageError::Timeout(timeout)),
+        },
+        None => operation.await.map_err(StorageError::Db),
+    }
+}
+
+// MySQL server errors worth retrying: ER_LOCK_WAIT_TIMEOUT and ER_LOCK_DEADLOCK. Reads retry
+// them here, and classify_db_error reports them as DATABASE_BUSY once retries are exhausted.
+pub(crate) const TRANSIENT_SERVER_ERRORS: [u16; 2] = [1205, 1213];
+
+// True if the statement may well succeed when run again: it hit a lock conflict, or the
+// connection broke underneath it (e.g. "server has gone away").
+// A timeout is not retried: the statement would most likely time out again.
+fn is_transient(e: &StorageError) -> bool {
+    match e {
+        StorageError::Db(mysql_async::Error::Io(_)) => true,
+        StorageError::Db(mysql_async::Error::Server(e)) => TRANSIENT_SERVER_ERRORS.contains(&e.code),
+        _ => false,
+    }
+}
+
+// How a statement is retried after a transient error. The backoff doubles each attempt.
+struct RetryPolicy {
+    retries: u32,
+    backoff: Duration,
+}
+
+// Where the orders are kept.
+enum Backend {
+    // Reads and writes use separate pools so a burst of one cannot starve the other.
+    MySql { read_pool: DbPool, write_pool: DbPool },
+    // Selected with DATABASE_URL=memory://. Nothing is persisted; for demos and tests.
+    Memory(Mutex<Vec<Order>>),
+}
+
+// All database access of the service.
+pub struct Storage {
+    backend: Backend,
+    // Statements slower than this many milliseconds are logged and kept in slow_queries.
+    slow_query_ms: u64,
+    slow_queries: Mutex<VecDeque<SlowQuery>>,
+    // For the idempotent SELECT behind /orders. Writes are never retried, since an INSERT
+    // whose acknowledgement was lost may already have been applied.
+    read_retry: RetryPolicy,
+    // Per-route limits on how long a database operation may take, including waiting for a
+    // connection. None waits forever.
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl Storage {
+    pub fn new(config: &Config) -> Self {
+        let backend = if config.database_url == MEMORY_DATABASE_URL {
+            Backend::Memory(Mutex::new(Vec::new()))
+        } else {
+            Backend::MySql {
+                read_pool: DbPool::new(&config.read_database_url, config.read_pool_size, config.pool_pre_ping),
+                write_pool: DbPool::new(&config.database_url, config.write_pool_size, config.pool_pre_ping),
+            }
+        };
+        Self {
+            backend,
+            slow_query_ms: config.slow_query_ms,
+            slow_queries: Mutex::new(VecDeque::with_capacity(SLOW_QUERY_LOG_SIZE)),
+            read_retry: RetryPolicy {
+                retries: config.read_retries,
+                backoff: Duration::from_millis(config.read_retry_backoff_ms),
+            },
+            read_timeout: Some(config.read_timeout_ms)
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            write_timeout: Some(config.write_timeout_ms)
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+        }
+    }
+
+    pub async fn warm_up(&self) -> Result<(), mysql_async::Error> {
+        match &self.backend {
+            Backend::MySql { read_pool, write_pool } => {
+                write_pool.warm_up().await?;
+                read_pool.warm_up().await
+            }
+            Backend::Memory(_) => Ok(()),
+        }
+    }
+
+    // Moving average of the time spent waiting for a read connection, in milliseconds.
+    pub fn read_wait_ms(&self) -> u64 {
+        match &self.backend {
+            Backend::MySql { read_pool, .. } => read_pool.wait_ms.get(),
+            Backend::Memory(_) => 0,
+        }
+    }
+
+    // Counts a read turned away by load shedding as a read that did not wait for a connection,
+    // so the average falls again once reads stop reaching the pool.
+    pub fn record_shed_read(&self) {
+        if let Backend::MySql { read_pool, .. } = &self.backend {
+            read_pool.wait_ms.record(0);
+        }
+    }
+
+    // The most recent slow queries, oldest first.
+    pub fn slow_queries(&self) -> Vec<SlowQuery> {
+        self.slow_queries.lock().unwrap().iter().cloned().collect()
+    }
+
+    // Records a statement that started at `start` if it exceeded the slow query threshold.
+    // `outcome` is its row count, or the error it failed with, e.g. after a lock wait timeout.
+    fn record_query(&self, sql: &str, start: Instant, outcome: Result<u64, &mysql_async::Error>) {
+        let duration_ms = start.elapsed().as_millis() as u64;
+        if duration_ms < self.slow_query_ms {
+            return;
+        }
+        let (rows, error) = match outcome {
+            Ok(rows) => {
+                eprintln!("slow query: {} ms, {} rows: {}", duration_ms, rows, sql);
+                (rows, None)
+            }
+            Err(e) => {
+                let error = match e {
+                    mysql_async::Error::Server(e) => format!("ERROR {} ({})", e.code, e.state),
+                    mysql_async::Error::Io(_) => "I/O error".into(),
+                    _ => "driver error".into(),
+                };
+                eprintln!("slow query: {} ms, failed with {}: {}", duration_ms, error, sql);
+                (0, Some(error))
+            }
+        };
+        let finished_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut slow_queries = self.slow_queries.lock().unwrap();
+        if slow_queries.len() == SLOW_QUERY_LOG_SIZE {
+            slow_queries.pop_front();
+        }
+        slow_queries.push_back(SlowQuery {
+            sql: sql.to_string(),
+            duration_ms,
+            rows,
+            error,
+            finished_at,
+        });
+    }
+
+    // Creates the orders table if it is missing. Existing data is kept.
+    pub async fn create_table_if_missing(&self) -> Result<(), mysql_async::Error> {
+        let write_pool = match &self.backend {
+            Backend::MySql { write_pool, .. } => write_pool,
+            Backend::Memory(_) => return Ok(()),
+        };
+        let mut conn = write_pool.get_conn().await?;
+        CREATE_ORDERS_TABLE.ignore(&mut conn).await
+    }
+
+    // Drops and recreates the orders table.
+    pub async fn init(&self) -> Result<(), StorageError> {
+        let write_pool = match &self.backend {
+            Backend::MySql { write_pool, .. } => write_pool,
+            Backend::Memory(orders) => {
+                orders.lock().unwrap().clear();
+                return Ok(());
+            }
+        };
+        with_timeout(self.write_timeout, self.exec_init(write_pool)).await
+    }
+
+    async fn exec_init(&self, write_pool: &DbPool) -> Result<(), mysql_async::Error> {
+        let mut conn = write_pool.get_conn().await?;
+        let query_start = Instant::now();
+        let result = DROP_ORDERS_TABLE.ignore(&mut conn).await;
+        self.record_query(DROP_ORDERS_TABLE, query_start, result.as_ref().map(|_| 0));
+        result?;
+        let query_start = Instant::now();
+        let result = CREATE_ORDERS_TABLE.ignore(&mut conn).await;
+        self.record_query(CREATE_ORDERS_TABLE, query_start, result.as_ref().map(|_| 0));
+        result
+    }
+
+    pub async fn create_order(&self, order: &Order) -> Result<(), StorageError> {
+        let write_pool = match &self.backend {
+            Backend::MySql { write_pool, .. } => write_pool,
+            Backend::Memory(orders) => {
+                orders.lock().unwrap().push(order.clone());
+                return Ok(());
+            }
+        };
+        with_timeout(self.write_timeout, self.exec_create_order(write_pool, order)).await
+    }
+
+    async fn exec_create_order(&self, write_pool: &DbPool, order: &Order) -> Result<(), mysql_async::Error> {
+        let mut conn = write_pool.get_conn().await?;
+        let query_start = Instant::now();
+        let result = INSERT_ORDER
+            .with(params! {
+                "order_id" => order.order_id,
+                "product_id" => order.product_id,
+                "quantity" => order.quantity,
+                "amount" => order.amount,
+                "shipping" => order.shipping,
+                "tax" => order.tax,
+                "shipping_address" => &order.shipping_address,
+            })
+            .ignore(&mut conn)
+            .await;
+        self.record_query(INSERT_ORDER, query_start, result.as_ref().map(|_| conn.affected_rows()));
+        result
+    }
+
+    pub async fn create_orders(&self, orders: &[Order]) -> Result<(), StorageError> {
+        let write_pool = match &self.backend {
+            Backend::MySql { write_pool, .. } => write_pool,
+            Backend::Memory(stored) => {
+                stored.lock().unwrap().extend_from_slice(orders);
+                return Ok(());
+            }
+        };
+        with_timeout(self.write_timeout, self.exec_create_orders(write_pool, orders)).await
+    }
+
+    async fn exec_create_orders(&self, write_pool: &DbPool, orders: &[Order]) -> Result<(), mysql_async::Error> {
+        let mut conn = write_pool.get_conn().await?;
+        let query_start = Instant::now();
+        let result = INSERT_ORDER
+            .with(orders.iter().map(|order| {
+                params! {
+                    "order_id" => order.order_id,
+                    "product_id" => order.product_id,
+                    "quantity" => order.quantity,
+                    "amount" => order.amount,
+                    "shipping" => order.shipping,
+                    "tax" => order.tax,
+                    "shipping_address" => &order.shipping_address,
+                }
+            }))
+            .batch(&mut conn)
+            .await;
+        self.record_query(INSERT_ORDER, query_start, result.as_ref().map(|_| orders.len() as u64));
+        result
+    }
+
+    pub async fn update_order(&self, order: &Order) -> Result<(), StorageError> {
+        let write_pool = match &self.backend {
+            Backend::MySql { write_pool, .. } => write_pool,
+            Backend::Memory(orders) => {
+                // Like the UPDATE, changes every row with this order_id.
+                for stored in orders.lock().unwrap().iter_mut().filter(|o| o.order_id == order.order_id) {
+                    *stored = order.clone();
+                }
+                return Ok(());
+            }
+        };
+        with_timeout(self.write_timeout, self.exec_update_order(write_pool, order)).await
+    }
+
+    async fn exec_update_order(&self, write_pool: &DbPool, order: &Order) -> Result<(), mysql_async::Error> {
+        let mut conn = write_pool.get_conn().await?;
+        let query_start = Instant::now();
+        let result = UPDATE_ORDER
+            .with(params! {
+                "product_id" => order.product_id,
+                "quantity" => order.quantity,
+                "amount" => order.amount,
+                "shipping" => order.shipping,
+                "tax" => order.tax,
+                "shipping_address" => &order.shipping_address,
+                "order_id" => order.order_id,
+            })
+            .ignore(&mut conn)
+            .await;
+        self.record_query(UPDATE_ORDER, query_start, result.as_ref().map(|_| conn.affected_rows()));
+        result
+    }
+
+    pub async fn orders(&self) -> Result<Vec<Order>, StorageError> {
+        let read_pool = match &self.backend {
+            Backend::MySql { read_pool, .. } => read_pool,
+            Backend::Memory(orders) => return Ok(orders.lock().unwrap().clone()),
+        };
+        let mut attempt = 0;
+        loop {
+            match with_timeout(self.read_timeout, self.select_orders(read_pool)).await {
+                Err(e) if attempt < self.read_retry.retries && is_transient(&e) => {
+                    let backoff = self.read_retry.backoff * 2u32.saturating_pow(attempt);
+                    eprintln!("retrying {} in {:?} after: {}", SELECT_ORDERS, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn select_orders(&self, read_pool: &DbPool) -> Result<Vec<Order>, mysql_async::Error> {
+        let mut conn = read_pool.get_conn().await?;
+        let query_start = Instant::now();
+        let result = SELECT_ORDERS
+            .with(())
+            .map(&mut conn, |(order_id, product_id, quantity, amount, shipping, tax, shipping_address)| {
+                Order::new(
+                    order_id,
+                    product_id,
+                    quantity,
+                    amount,
+                    shipping,
+                    tax,
+                    shipping_address,
+                )},
+            ).await;
+        self.record_query(SELECT_ORDERS, query_start, result.as_ref().map(|orders| orders.len() as u64));
+        result
+    }
+
+    pub async fn delete_order(&self, order_id: Option<i32>) -> Result<(), StorageError> {
+        let write_pool = match &self.backend {
+            Backend::MySql { write_pool, .. } => write_pool,
+            Backend::Memory(orders) => {
+                // A missing id matches nothing, as NULL does in the DELETE.
+                if let Some(order_id) = order_id {
+                    orders.lock().unwrap().retain(|o| o.order_id != order_id);
+                }
+                return Ok(());
+            }
+        };
+        with_timeout(self.write_timeout, self.exec_delete_order(write_pool, order_id)).await
+    }
+
+    async fn exec_delete_order(&self, write_pool: &DbPool, order_id: Option<i32>) -> Result<(), mysql_async::Error> {
+        let mut conn = write_pool.get_conn().await?;
+        let query_start = Instant::now();
+        let result = DELETE_ORDER
+            .with(params! { "order_id" => order_id, })
+            .ignore(&mut conn)
+            .await;
+        self.record_query(DELETE_ORDER, query_start, result.as_ref().map(|_| conn.affected_rows()));
+        result
+    }
+
+    // Compares the live orders table with ORDERS_COLUMNS. Returns None if the table does not
+    // exist yet, otherwise one line per difference.
+    pub async fn orders_schema_diff(&self) -> Result<Option<Vec<String>>, mysql_async::Error> {
+        let write_pool = match &self.backend {
+            Backend::MySql { write_pool, .. } => write_pool,
+            Backend::Memory(_) => return Ok(Some(Vec::new())),
+        };
+        let mut conn = write_pool.pool.get_conn().await?;
+        let columns: Vec<(String, String, Option<u64>)> = "SELECT COLUMN_NAME, DATA_TYPE, CHARACTER_MAXIMUM_LENGTH FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'orders' ORDER BY ORDINAL_POSITION"
+            .fetch(&mut conn)
+            .await?;
+        drop(conn);
+        if columns.is_empty() {
+            return Ok(None);
+        }
+
+        let describe = |name: &str, data_type: &str, len: Option<u64>| match len {
+            Some(len) => format!("{} {}({})", name, data_type, len),
+            None => format!("{} {}", name, data_type),
+        };
+        let mut diff = Vec::new();
+        for i in 0..ORDERS_COLUMNS.len().max(columns.len()) {
+            let expected = ORDERS_COLUMNS.get(i).map(|&(name, data_type, len)| describe(name, data_type, len));
+            let found = columns
+                .get(i)
+                .map(|(name, data_type, len)| describe(&name.to_lowercase(), &data_type.to_lowercase(), *len));
+            match (expected, found) {
+                (Some(expected), Some(found)) if expected != found => {
+                    diff.push(format!("column {}: expected `{}`, found `{}`", i + 1, expected, found))
+                }
+                (Some(expected), None) => diff.push(format!("column {}: missing `{}`", i + 1, expected)),
+                (None, Some(found)) => diff.push(format!("column {}: unexpected `{}`", i + 1, found)),
+                _ => {}
+            }
+        }
+        Ok(Some(diff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_error(code: u16) -> StorageError {
+        StorageError::Db(mysql_async::Error::Server(mysql_async::ServerError {
+            code,
+            message: String::new(),
+            state: "HY000".into(),
+        }))
+    }
+
+    #[test]
+    fn retries_lock_errors_and_dropped_connections() {
+        assert!(is_transient(&server_error(1205)));
+        assert!(is_transient(&server_error(1213)));
+        let io = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(is_transient(&StorageError::Db(io.into())));
+    }
+
+    #[test]
+    fn does_not_retry_timeouts_or_other_errors() {
+        assert!(!is_transient(&StorageError::Timeout(Duration::from_secs(5))));
+        assert!(!is_transient(&server_error(1062)));
+        assert!(!is_transient(&server_error(1146)));
+    }
+}
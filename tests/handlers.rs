@@ -0,0 +1,189 @@
+// Drives handle_request end to end on the in-memory backend, so no MySQL is needed.
+use hyper::header::{ACCEPT, ALLOW, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Request, StatusCode};
+use order_demo_service::config::{Config, MEMORY_DATABASE_URL};
+use order_demo_service::models::Order;
+use order_demo_service::routes::AppState;
+use order_demo_service::{create_app, handle_request};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+async fn app(config: Config) -> Arc<AppState> {
+    create_app(Config {
+        database_url: MEMORY_DATABASE_URL.into(),
+        ..config
+    })
+    .await
+    .unwrap()
+}
+
+fn get(uri: &str) -> Request<Body> {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+fn post_json(uri: &str, body: &Value) -> Request<Body> {
+    Request::post(uri)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn order(order_id: i32, quantity: i32, shipping_address: &str) -> Value {
+    json!({
+        "order_id": order_id,
+        "product_id": 12,
+        "quantity": quantity,
+        "amount": 56.0,
+        "shipping": 15.0,
+        "tax": 2.0,
+        "shipping_address": shipping_address,
+    })
+}
+
+async fn send(state: &Arc<AppState>, req: Request<Body>) -> (StatusCode, Value) {
+    let resp = handle_request(req, state.clone()).await.unwrap();
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+async fn orders(state: &Arc<AppState>) -> Vec<Order> {
+    let (status, body) = send(state, get("/v1/orders")).await;
+    assert_eq!(status, StatusCode::OK);
+    serde_json::from_value(body).unwrap()
+}
+
+// Sends `req` and checks that it fails with `status` and the problem `code`.
+async fn assert_problem(state: &Arc<AppState>, req: Request<Body>, status: StatusCode, code: &str) {
+    let (got_status, body) = send(state, req).await;
+    assert_eq!(got_status, status, "{}", body);
+    assert_eq!(body["code"], code);
+    assert_eq!(body["status"], status.as_u16());
+}
+
+#[tokio::test]
+async fn create_list_update_delete() {
+    let state = app(Config::default()).await;
+    assert_eq!(send(&state, get("/v1/init")).await, (StatusCode::OK, json!({"status": true})));
+
+    let (status, _) = send(&state, post_json("/v1/create_order", &order(1, 2, "Mataderos 2312"))).await;
+    assert_eq!(status, StatusCode::OK);
+    let batch = json!([order(2, 1, "Obere Str. 57"), order(3, 5, "Avda. de la Constitución 2222")]);
+    let (status, _) = send(&state, post_json("/v1/create_orders", &batch)).await;
+    assert_eq!(status, StatusCode::OK);
+    let ids: Vec<i32> = orders(&state).await.iter().map(|o| o.order_id).collect();
+    assert_eq!(ids, [1, 2, 3]);
+
+    let (status, _) = send(&state, post_json("/v1/update_order", &order(2, 7, "Berguvsvägen 8"))).await;
+    assert_eq!(status, StatusCode::OK);
+    let updated = orders(&state).await.into_iter().find(|o| o.order_id == 2).unwrap();
+    assert_eq!((updated.quantity, updated.shipping_address.as_str()), (7, "Berguvsvägen 8"));
+
+    let (status, _) = send(&state, get("/v1/delete_order?id=2")).await;
+    assert_eq!(status, StatusCode::OK);
+    let ids: Vec<i32> = orders(&state).await.iter().map(|o| o.order_id).collect();
+    assert_eq!(ids, [1, 3]);
+
+    // The legacy unversioned routes reach the same handlers.
+    let (status, _) = send(&state, get("/delete_order?id=1")).await;
+    assert_eq!(status, StatusCode::OK);
+    let ids: Vec<i32> = orders(&state).await.iter().map(|o| o.order_id).collect();
+    assert_eq!(ids, [3]);
+}
+
+#[tokio::test]
+async fn delete_order_rejects_non_numeric_id() {
+    let state = app(Config::default()).await;
+    send(&state, post_json("/v1/create_order", &order(2, 1, "Obere Str. 57"))).await;
+
+    for uri in ["/v1/delete_order?id=abc", "/v1/delete_order?id=2x"] {
+        assert_problem(&state, get(uri), StatusCode::BAD_REQUEST, "INVALID_PARAMETER").await;
+    }
+    assert_eq!(orders(&state).await.len(), 1);
+}
+
+#[tokio::test]
+async fn unknown_path_is_404() {
+    let state = app(Config::default()).await;
+    assert_problem(&state, get("/v1/nope"), StatusCode::NOT_FOUND, "NOT_FOUND").await;
+}
+
+#[tokio::test]
+async fn wrong_method_is_405() {
+    let state = app(Config::default()).await;
+    assert_problem(&state, get("/v1/create_order"), StatusCode::METHOD_NOT_ALLOWED, "METHOD_NOT_ALLOWED").await;
+    let resp = handle_request(get("/v1/create_order"), state.clone()).await.unwrap();
+    assert_eq!(resp.headers()[ALLOW], "POST, OPTIONS");
+}
+
+#[tokio::test]
+async fn non_json_accept_is_406() {
+    let state = app(Config::default()).await;
+    let req = Request::get("/v1/orders").header(ACCEPT, "text/html").body(Body::empty()).unwrap();
+    assert_problem(&state, req, StatusCode::NOT_ACCEPTABLE, "NOT_ACCEPTABLE").await;
+}
+
+#[tokio::test]
+async fn oversized_body_is_413() {
+    let state = app(Config {
+        max_body_bytes: 64,
+        ..Config::default()
+    })
+    .await;
+    let address = "x".repeat(100);
+    assert_problem(
+        &state,
+        post_json("/v1/create_order", &order(1, 1, &address)),
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "PAYLOAD_TOO_LARGE",
+    )
+    .await;
+
+    // A Content-Length over the limit is rejected before the body is read.
+    let body = order(1, 1, &address).to_string();
+    let req = Request::post("/v1/create_order")
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .unwrap();
+    assert_problem(&state, req, StatusCode::PAYLOAD_TOO_LARGE, "PAYLOAD_TOO_LARGE").await;
+
+    // Without a Content-Length the limit is enforced while reading the body.
+    let (mut sender, body) = Body::channel();
+    let chunk = order(1, 1, &address).to_string();
+    tokio::spawn(async move { sender.send_data(chunk.into()).await });
+    let req = Request::post("/v1/create_order")
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .unwrap();
+    assert_problem(&state, req, StatusCode::PAYLOAD_TOO_LARGE, "PAYLOAD_TOO_LARGE").await;
+    assert!(orders(&state).await.is_empty());
+}
+
+#[tokio::test]
+async fn non_json_content_type_is_415() {
+    let state = app(Config::default()).await;
+    let req = Request::post("/v1/create_order")
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from(order(1, 1, "Obere Str. 57").to_string()))
+        .unwrap();
+    assert_problem(&state, req, StatusCode::UNSUPPORTED_MEDIA_TYPE, "UNSUPPORTED_MEDIA_TYPE").await;
+}
+
+#[tokio::test]
+async fn invalid_order_is_422() {
+    let state = app(Config::default()).await;
+    let address = "x".repeat(257);
+    assert_problem(
+        &state,
+        post_json("/v1/create_order", &order(1, 1, &address)),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "INVALID_ORDER",
+    )
+    .await;
+    let batch = json!([order(1, 1, "Obere Str. 57"), order(2, 1, &address)]);
+    let (status, body) = send(&state, post_json("/v1/create_orders", &batch)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["detail"].as_str().unwrap().starts_with("orders[1]: "), "{}", body);
+    assert!(orders(&state).await.is_empty());
+}